@@ -1,4 +1,13 @@
-use std::{ffi::OsStr, io::BufRead, ops::Not};
+//! Compiles `.gitignore`-style ignore files into matchers that can be tested
+//! against a path while `WalkDir` is traversing it, rather than expanding
+//! globs into concrete paths up front.
+
+use std::{
+    ffi::OsStr,
+    io::BufRead,
+    ops::Not,
+    path::{Path, PathBuf},
+};
 use regex::Regex;
 
 use lazy_static::lazy_static;
@@ -7,13 +16,16 @@ pub struct IgnoreDirs {
 }
 
 impl IgnoreDirs {
-    pub fn query_from(root_dir: &str) -> Vec<String> {
-        let ignore_files_path = IgnoreDirs::get_all_ignore_files(root_dir);
-        
-        ignore_files_path.iter()
-            .map(|f| IgnoreDirs::get_all_ignore_paths_from_ignore_file(f))
-            .flatten()
-            .collect::<Vec<String>>()
+    /// Compile every ignore file directly inside `dir` (e.g. `.gitignore`,
+    /// `.dockerignore`) into a flat, ordered list of patterns. Order matters:
+    /// a later `!`-negated pattern can re-include a path an earlier pattern
+    /// excluded.
+    pub fn compile_dir(dir: &Path) -> Vec<IgnorePattern> {
+        IgnoreDirs::get_all_ignore_files(dir)
+            .iter()
+            .flat_map(|f| IgnoreDirs::get_all_ignore_paths_from_ignore_file(f))
+            .filter_map(|line| IgnorePattern::parse(&line))
+            .collect()
     }
 
     fn is_ignore_file_pattern(input: &OsStr) -> bool {
@@ -25,14 +37,17 @@ impl IgnoreDirs {
         RE.is_match(input.to_str().unwrap())
     }
 
-    fn get_all_ignore_files(dir: &str) -> Vec<String> {
-        std::fs::read_dir(dir)
-            .unwrap()
+    fn get_all_ignore_files(dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return vec![];
+        };
+        entries
             .into_iter()
-            .map(|file| file.unwrap().file_name())
-            .filter(|file_name| IgnoreDirs::is_ignore_file_pattern(&file_name))
-            .map(|f| format!("{}/{}", dir, f.to_str().unwrap()))
-            .collect::<Vec<String>>()
+            .filter_map(|file| file.ok())
+            .map(|file| file.file_name())
+            .filter(|file_name| IgnoreDirs::is_ignore_file_pattern(file_name))
+            .map(|f| dir.join(f))
+            .collect::<Vec<_>>()
     }
 
     fn is_ignore_path_pattern(input: &str) -> bool {
@@ -44,21 +59,152 @@ impl IgnoreDirs {
         RE.is_match(input).not()
     }
 
-    fn get_all_ignore_paths_from_ignore_file(input: &str) -> Vec<String> {        
+    fn get_all_ignore_paths_from_ignore_file(input: &Path) -> Vec<String> {
         let file = std::fs::File::open(input)
-            .expect(format!("failed to open file ({})", input).as_str());
-        
+            .unwrap_or_else(|_| panic!("failed to open file ({})", input.to_string_lossy()));
+
         std::io::BufReader::new(file)
             .lines()
-            .map(|line| line.unwrap())    
+            .map(|line| line.unwrap())
             .filter(|line| IgnoreDirs::is_ignore_path_pattern(line))
             .collect::<Vec<String>>()
     }
 }
 
+/// One compiled line from an ignore file.
+pub struct IgnorePattern {
+    regex: Regex,
+    negate: bool,
+    dir_only: bool,
+}
+
+impl IgnorePattern {
+    fn parse(line: &str) -> Option<Self> {
+        let mut line = line;
+
+        let negate = line.starts_with('!');
+        if negate {
+            line = &line[1..];
+        }
+
+        let anchored = line.starts_with('/');
+        if anchored {
+            line = &line[1..];
+        }
+
+        let dir_only = line.ends_with('/') && !line.ends_with("\\/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+
+        if line.is_empty() {
+            return None;
+        }
+
+        // A pattern with a slash anywhere but at the very end (already
+        // stripped above as `dir_only`) is anchored to the ignore file's
+        // directory under real gitignore semantics, not just one with a
+        // leading `/`.
+        let anchored = anchored || line.contains('/');
+
+        Some(Self {
+            regex: glob_to_regex(line, anchored),
+            negate,
+            dir_only,
+        })
+    }
+
+    fn applies_to(&self, is_dir: bool) -> bool {
+        is_dir || !self.dir_only
+    }
+}
+
+/// A stack of ignore-file rule sets, one frame per ancestor directory that
+/// had its own ignore file, mirroring how git itself layers `.gitignore`
+/// files down a tree. Rules from a nested ignore file only ever affect paths
+/// underneath the directory that defined them.
+#[derive(Default)]
+pub struct IgnoreStack {
+    frames: Vec<(PathBuf, Vec<IgnorePattern>)>,
+}
+
+impl IgnoreStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call once for every directory the walk descends into, before testing
+    /// any of its children. Pops frames belonging to subtrees we've left and
+    /// loads `dir`'s own ignore files, if any, as a new frame.
+    pub fn enter_dir(&mut self, dir: &Path) {
+        self.frames.retain(|(base, _)| dir.starts_with(base));
+        let patterns = IgnoreDirs::compile_dir(dir);
+        if !patterns.is_empty() {
+            self.frames.push((dir.to_path_buf(), patterns));
+        }
+    }
+
+    /// Whether `path` is excluded by the rules accumulated so far. Patterns
+    /// are tested oldest-frame-first and in file order within a frame, with
+    /// the last matching pattern winning, so a later `!pattern` can override
+    /// an earlier exclusion.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for (base, patterns) in &self.frames {
+            let Ok(rel) = path.strip_prefix(base) else {
+                continue;
+            };
+            let rel = rel.to_string_lossy().replace('\\', "/");
+            for pattern in patterns {
+                if pattern.applies_to(is_dir) && pattern.regex.is_match(&rel) {
+                    ignored = !pattern.negate;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+/// Translate a single gitignore glob into an anchored regex. `**` matches
+/// any number of path components (including none), `*` matches within a
+/// single component and `?` matches a single character other than `/`.
+fn glob_to_regex(pattern: &str, anchored: bool) -> Regex {
+    let mut out = String::from("^");
+    if !anchored {
+        out.push_str("(?:.*/)?");
+    }
+
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    out.push_str("(?:.*/)?");
+                } else {
+                    out.push_str(".*");
+                }
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '.' | '+' | '(' | ')' | '|' | '^' | '$' | '{' | '}' | '[' | ']' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push('$');
+
+    Regex::new(&out).expect("ignore pattern should translate to a valid regex")
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{glob_to_regex, IgnorePattern, IgnoreStack};
     use crate::ignore_dirs::IgnoreDirs;
+    use std::path::Path;
 
     #[test]
     fn check_is_ignore_file_pattern() {
@@ -72,11 +218,11 @@ mod tests {
             ("README.md", false),
             (".IGNORE", false)
         ];
-        
+
         for (input, expected) in samples {
             assert_eq!(
-                IgnoreDirs::is_ignore_file_pattern(std::ffi::OsStr::new(input)), 
-                expected, 
+                IgnoreDirs::is_ignore_file_pattern(std::ffi::OsStr::new(input)),
+                expected,
                 "input ({}) should be {}", input, expected);
         }
     }
@@ -90,12 +236,107 @@ mod tests {
             ("build", true),
             (".editorconfig", true)
         ];
-        
+
         for (input, expected) in samples {
             assert_eq!(
-                IgnoreDirs::is_ignore_path_pattern(input), 
-                expected, 
+                IgnoreDirs::is_ignore_path_pattern(input),
+                expected,
                 "input ({}) should be {}", input, expected);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn check_glob_to_regex_matches_any_depth_by_default() {
+        let re = glob_to_regex("*.rs", false);
+        assert!(re.is_match("main.rs"));
+        assert!(re.is_match("src/main.rs"));
+        assert!(!re.is_match("src/main.rsx"));
+    }
+
+    #[test]
+    fn check_glob_to_regex_anchored() {
+        let re = glob_to_regex("build", true);
+        assert!(re.is_match("build"));
+        assert!(!re.is_match("src/build"));
+    }
+
+    #[test]
+    fn check_glob_to_regex_double_star() {
+        let re = glob_to_regex("foo/**/bar", true);
+        assert!(re.is_match("foo/bar"));
+        assert!(re.is_match("foo/a/b/bar"));
+        assert!(!re.is_match("foo/bar2"));
+    }
+
+    #[test]
+    fn check_ignore_pattern_dir_only() {
+        let pattern = IgnorePattern::parse("build/").expect("should parse");
+        assert!(pattern.applies_to(true));
+        assert!(!pattern.applies_to(false));
+    }
+
+    #[test]
+    fn check_ignore_pattern_negate() {
+        let pattern = IgnorePattern::parse("!keep.log").expect("should parse");
+        assert!(pattern.negate);
+        assert!(pattern.regex.is_match("keep.log"));
+    }
+
+    #[test]
+    fn check_ignore_stack_negation_overrides_earlier_match() {
+        let dir = std::env::temp_dir().join(format!(
+            "srcprof-ignore-stack-test-{:?}",
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".gitignore"), "*.log\n!keep.log\n").unwrap();
+
+        let mut stack = IgnoreStack::new();
+        stack.enter_dir(&dir);
+
+        assert!(stack.is_ignored(&dir.join("debug.log"), false));
+        assert!(!stack.is_ignored(&dir.join("keep.log"), false));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_ignore_stack_mid_slash_pattern_is_anchored() {
+        let root = std::env::temp_dir().join(format!(
+            "srcprof-ignore-stack-mid-slash-{:?}",
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("other/src")).unwrap();
+        std::fs::write(&root.join(".gitignore"), "src/skip.rs\n").unwrap();
+
+        let mut stack = IgnoreStack::new();
+        stack.enter_dir(&root);
+
+        assert!(stack.is_ignored(&root.join("src/skip.rs"), false));
+        assert!(!stack.is_ignored(&root.join("other/src/skip.rs"), false));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn check_ignore_stack_scoped_to_subtree() {
+        let root = std::env::temp_dir().join(format!(
+            "srcprof-ignore-stack-scope-{:?}",
+            std::thread::current().id()
+        ));
+        let nested = root.join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(nested.join(".gitignore"), "*.tmp\n").unwrap();
+
+        let mut stack = IgnoreStack::new();
+        stack.enter_dir(&root);
+        assert!(!stack.is_ignored(&root.join("scratch.tmp"), false));
+
+        stack.enter_dir(&nested);
+        assert!(stack.is_ignored(&nested.join("scratch.tmp"), false));
+
+        std::fs::remove_dir_all(&root).unwrap();
+        let _ = Path::new(&root);
+    }
+}
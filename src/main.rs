@@ -10,7 +10,7 @@ use git2::{Repository, TreeWalkResult};
 use rayon::prelude::*;
 use std::{
     cmp::Reverse,
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     ffi::OsString,
     fs::File,
@@ -20,6 +20,9 @@ use std::{
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
+mod ignore_dirs;
+use ignore_dirs::IgnoreStack;
+
 #[derive(Debug, StructOpt)]
 struct Opt {
     #[structopt(help = "Root directory to profile")]
@@ -43,16 +46,34 @@ struct Opt {
     no_summary: bool,
     #[structopt(short = "d", long, help = "Show statistics summary")]
     no_distrib: bool,
-    #[cfg(feature = "git2")]
+    #[cfg(any(feature = "git2", feature = "gix"))]
     #[structopt(short = "g", long, help = "Load from git repository")]
     use_git: bool,
-    #[cfg(feature = "git2")]
+    #[cfg(any(feature = "git2", feature = "gix"))]
     #[structopt(
         short = "b",
         long,
         help = "Git branch name to search line numbers. If omitted, HEAD is used."
     )]
     branch: Option<String>,
+    #[cfg(all(feature = "git2", feature = "gix"))]
+    #[structopt(
+        long,
+        help = "Use the pure-Rust gix backend instead of git2 for -g/--use-git (requires building with the \"gix\" feature)"
+    )]
+    gix: bool,
+    #[cfg(feature = "git2")]
+    #[structopt(
+        long,
+        help = "Walk commit history and report how source volume churned over the range, instead of a single snapshot"
+    )]
+    churn: bool,
+    #[cfg(feature = "git2")]
+    #[structopt(
+        long,
+        help = "Bucket --churn into a time series grouped by \"day\" or \"commit\". If omitted, only the totals for the whole range are reported."
+    )]
+    churn_by: Option<String>,
     #[structopt(short, long, help = "Add an entry to list of extensions to search")]
     extensions: Vec<String>,
     #[structopt(
@@ -73,6 +94,30 @@ struct Opt {
         help = "Count file size in utf-8 characters instead of bytes"
     )]
     utf8: bool,
+    #[structopt(
+        short = "t",
+        long,
+        help = "Show a directory tree of line counts with proportion bars, instead of flat by-extension aggregation"
+    )]
+    tree: bool,
+    #[structopt(long, help = "Limit the directory tree to this many levels (used with --tree)")]
+    depth: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Collapse directory tree entries contributing less than this percentage of their parent into a single \"...\" entry (used with --tree)"
+    )]
+    min_percent: f64,
+    #[structopt(
+        long,
+        help = "Group the summary by language instead of raw file extension, resolving extensionless scripts via their shebang line"
+    )]
+    by_language: bool,
+    #[structopt(
+        long,
+        help = "Add or override an extension-to-language mapping as ext=Language (e.g. ts=TypeScript), used with --by-language"
+    )]
+    language_map: Vec<String>,
 }
 
 fn main() -> Result<()> {
@@ -84,12 +129,38 @@ fn main() -> Result<()> {
     );
 
     #[cfg(feature = "git2")]
+    if settings.churn {
+        let report = process_churn_git(&settings)?;
+
+        if settings.enable_html {
+            println!(
+                r#"
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en">
+<head>
+<title>srcprof.py output</title>
+</head>
+<body>
+"#
+            )
+        }
+
+        show_churn(&settings, &report);
+
+        if settings.enable_html {
+            println!("</body></html>");
+        }
+
+        return Ok(());
+    }
+
+    #[cfg(any(feature = "git2", feature = "gix"))]
     let (mut file_list, extstats) = if settings.use_git {
         process_files_git(&settings.root, &settings)?
     } else {
         process_files(&settings.root, &settings)?
     };
-    #[cfg(not(feature = "git2"))]
+    #[cfg(not(any(feature = "git2", feature = "gix")))]
     let (mut file_list, extstats) = process_files(&settings.root, &settings)?;
 
     if settings.enable_html {
@@ -111,6 +182,8 @@ fn main() -> Result<()> {
 
     show_distribution(&settings, &file_list, |v| v);
 
+    show_tree(&settings, &file_list);
+
     if settings.enable_html {
         println!("</body></html>");
     }
@@ -126,14 +199,25 @@ struct Settings {
     ranking: u32,
     summary: bool,
     enable_distrib: bool,
-    #[cfg(feature = "git2")]
+    #[cfg(any(feature = "git2", feature = "gix"))]
     use_git: bool,
-    #[cfg(feature = "git2")]
+    #[cfg(any(feature = "git2", feature = "gix"))]
     branch: Option<String>,
+    #[cfg(all(feature = "git2", feature = "gix"))]
+    gix: bool,
+    #[cfg(feature = "git2")]
+    churn: bool,
+    #[cfg(feature = "git2")]
+    churn_by: Option<ChurnGranularity>,
     extensions: HashSet<OsString>,
     ignore_dirs: HashSet<OsString>,
     human_readable: bool,
     utf8: bool,
+    tree: bool,
+    tree_depth: Option<usize>,
+    tree_min_percent: f64,
+    by_language: bool,
+    language_table: LanguageTable,
 }
 
 // It's a bit awkward to convert from Opt to Settings, but some settings are hard to write
@@ -141,8 +225,8 @@ struct Settings {
 impl From<Opt> for Settings {
     fn from(src: Opt) -> Self {
         let default_exts = [
-            ".sh", ".js", ".tcl", ".pl", ".py", ".rb", ".c", ".cpp", ".h", ".rc", ".rci", ".dlg",
-            ".pas", ".dpr", ".cs", ".rs",
+            ".sh", ".js", ".mjs", ".cjs", ".tcl", ".pl", ".py", ".rb", ".c", ".cpp", ".cc", ".cxx",
+            ".h", ".hpp", ".hxx", ".rc", ".rci", ".dlg", ".pas", ".dpr", ".cs", ".rs",
         ];
         let default_ignore_dirs = [".hg", ".svn", ".git", ".bzr", "node_modules", "target"]; // Probably we could ignore all directories beginning with a dot.
 
@@ -158,10 +242,19 @@ impl From<Opt> for Settings {
             ranking: src.ranking,
             summary: !src.no_summary,
             enable_distrib: !src.no_distrib,
-            #[cfg(feature = "git2")]
+            #[cfg(any(feature = "git2", feature = "gix"))]
             use_git: src.use_git,
-            #[cfg(feature = "git2")]
+            #[cfg(any(feature = "git2", feature = "gix"))]
             branch: src.branch,
+            #[cfg(all(feature = "git2", feature = "gix"))]
+            gix: src.gix,
+            #[cfg(feature = "git2")]
+            churn: src.churn,
+            #[cfg(feature = "git2")]
+            churn_by: src.churn_by.map(|s| {
+                s.parse()
+                    .unwrap_or_else(|e| panic!("--churn-by: {e}"))
+            }),
             extensions: if src.extensions.is_empty() {
                 default_exts.iter().map(|ext| ext[1..].into()).collect()
             } else {
@@ -182,6 +275,21 @@ impl From<Opt> for Settings {
             },
             human_readable: src.human_readable,
             utf8: src.utf8,
+            tree: src.tree,
+            tree_depth: src.depth,
+            tree_min_percent: src.min_percent,
+            by_language: src.by_language,
+            language_table: {
+                let mut table = default_language_table();
+                for mapping in &src.language_map {
+                    let (ext, lang) = mapping
+                        .split_once('=')
+                        .unwrap_or_else(|| panic!("--language-map expects ext=Language, got \"{mapping}\""));
+                    let ext = OsString::from(ext.trim_start_matches('.')).to_ascii_lowercase();
+                    table.insert(ext, lang.to_owned());
+                }
+                table
+            },
         }
     }
 }
@@ -190,6 +298,218 @@ struct FileEntry {
     name: PathBuf,
     lines: usize,
     size: u64,
+    /// The key this file is aggregated under in `SrcStatsSet`: a raw
+    /// extension normally, or a resolved language name under `--by-language`.
+    group: OsString,
+}
+
+/// Maps a lowercased, dot-less extension (e.g. "cpp") to a human-facing
+/// language name (e.g. "C++"), used to merge related extensions into one
+/// `--by-language` summary row. Extensions with no entry keep their raw
+/// extension as the group name.
+type LanguageTable = HashMap<OsString, String>;
+
+fn default_language_table() -> LanguageTable {
+    let pairs: &[(&str, &str)] = &[
+        ("c", "C"),
+        ("h", "C"),
+        ("cpp", "C++"),
+        ("cc", "C++"),
+        ("cxx", "C++"),
+        ("hpp", "C++"),
+        ("hxx", "C++"),
+        ("rs", "Rust"),
+        ("py", "Python"),
+        ("rb", "Ruby"),
+        ("js", "JavaScript"),
+        ("mjs", "JavaScript"),
+        ("cjs", "JavaScript"),
+        ("sh", "Shell"),
+        ("pl", "Perl"),
+        ("tcl", "Tcl"),
+        ("cs", "C#"),
+        ("pas", "Pascal"),
+        ("dpr", "Pascal"),
+        ("rc", "Resource"),
+        ("rci", "Resource"),
+        ("dlg", "Dialog"),
+    ];
+    pairs
+        .iter()
+        .map(|(ext, lang)| (OsString::from(ext), lang.to_string()))
+        .collect()
+}
+
+/// Maps a shebang interpreter name to the extension whose language entry it
+/// should be counted under, so `#!/usr/bin/env python3` lands in the same
+/// group as `.py` files.
+fn shebang_interpreter_ext(interpreter: &str) -> Option<&'static str> {
+    match interpreter {
+        "sh" | "bash" | "dash" | "zsh" => Some("sh"),
+        "python" | "python2" | "python3" => Some("py"),
+        "node" | "nodejs" => Some("js"),
+        "perl" => Some("pl"),
+        "ruby" => Some("rb"),
+        "tclsh" | "wish" => Some("tcl"),
+        _ => None,
+    }
+}
+
+/// Extracts the interpreter name out of a `#!...` line, following `env` to
+/// the program it invokes (e.g. `#!/usr/bin/env python3` -> `python3`).
+fn shebang_interpreter(line: &str) -> Option<&str> {
+    let mut parts = line.strip_prefix("#!")?.split_whitespace();
+    let program = parts.next()?;
+    let name = program.rsplit('/').next().unwrap_or(program);
+    if name == "env" {
+        let arg = parts.next()?;
+        Some(arg.rsplit('/').next().unwrap_or(arg))
+    } else {
+        Some(name)
+    }
+}
+
+/// Decides which `SrcStatsSet` key a file belongs under: its raw extension
+/// unless `--by-language` is set, in which case it's resolved through
+/// `settings.language_table`, falling back to a shebang line for files with
+/// no extension at all; a file whose extension is present but unrecognized
+/// is not checked for a shebang. Returns `None` when the file can't be
+/// classified, meaning it should be dropped rather than counted.
+fn resolve_group(settings: &Settings, ext: Option<OsString>, first_line: Option<&str>) -> Option<OsString> {
+    let ext = match ext {
+        Some(ext) => ext.to_ascii_lowercase(),
+        None if settings.by_language => {
+            let interpreter = first_line
+                .filter(|line| line.starts_with("#!"))
+                .and_then(shebang_interpreter)?;
+            OsString::from(shebang_interpreter_ext(interpreter)?)
+        }
+        None => return None,
+    };
+
+    if !settings.by_language {
+        return Some(ext);
+    }
+
+    Some(match settings.language_table.get(&ext) {
+        Some(lang) => OsString::from(lang),
+        None => ext,
+    })
+}
+
+#[cfg(test)]
+mod language_tests {
+    use super::{resolve_group, shebang_interpreter, shebang_interpreter_ext, Settings};
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    fn test_settings(by_language: bool, language_table: super::LanguageTable) -> Settings {
+        Settings {
+            root: PathBuf::new(),
+            listing: false,
+            enable_html: false,
+            ranking: 10,
+            summary: true,
+            enable_distrib: true,
+            #[cfg(any(feature = "git2", feature = "gix"))]
+            use_git: false,
+            #[cfg(any(feature = "git2", feature = "gix"))]
+            branch: None,
+            #[cfg(all(feature = "git2", feature = "gix"))]
+            gix: false,
+            #[cfg(feature = "git2")]
+            churn: false,
+            #[cfg(feature = "git2")]
+            churn_by: None,
+            extensions: HashSet::new(),
+            ignore_dirs: HashSet::new(),
+            human_readable: false,
+            utf8: false,
+            tree: false,
+            tree_depth: None,
+            tree_min_percent: 0.,
+            by_language,
+            language_table,
+        }
+    }
+
+    #[test]
+    fn check_shebang_interpreter() {
+        let samples = [
+            ("#!/bin/bash", Some("bash")),
+            ("#!/usr/bin/env python3", Some("python3")),
+            ("#!/usr/bin/env", None),
+            ("not a shebang", None),
+        ];
+        for (input, expected) in samples {
+            assert_eq!(
+                shebang_interpreter(input),
+                expected,
+                "input ({input}) should be {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_shebang_interpreter_ext() {
+        let samples = [
+            ("bash", Some("sh")),
+            ("python3", Some("py")),
+            ("nodejs", Some("js")),
+            ("wish", Some("tcl")),
+            ("unknown-lang", None),
+        ];
+        for (input, expected) in samples {
+            assert_eq!(
+                shebang_interpreter_ext(input),
+                expected,
+                "input ({input}) should be {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_resolve_group_uses_raw_extension_without_by_language() {
+        let settings = test_settings(false, super::default_language_table());
+        assert_eq!(
+            resolve_group(&settings, Some(OsString::from("CPP")), None),
+            Some(OsString::from("cpp"))
+        );
+    }
+
+    #[test]
+    fn check_resolve_group_merges_related_extensions_by_language() {
+        let settings = test_settings(true, super::default_language_table());
+        assert_eq!(
+            resolve_group(&settings, Some(OsString::from("hpp")), None),
+            Some(OsString::from("C++"))
+        );
+        assert_eq!(
+            resolve_group(&settings, Some(OsString::from("cpp")), None),
+            Some(OsString::from("C++"))
+        );
+    }
+
+    #[test]
+    fn check_resolve_group_falls_back_to_shebang_when_extensionless() {
+        let settings = test_settings(true, super::default_language_table());
+        assert_eq!(
+            resolve_group(&settings, None, Some("#!/usr/bin/env python3")),
+            Some(OsString::from("Python"))
+        );
+        assert_eq!(resolve_group(&settings, None, Some("no shebang here")), None);
+        assert_eq!(resolve_group(&settings, None, None), None);
+    }
+
+    #[test]
+    fn check_resolve_group_drops_extensionless_without_by_language() {
+        let settings = test_settings(false, super::default_language_table());
+        assert_eq!(
+            resolve_group(&settings, None, Some("#!/usr/bin/env python3")),
+            None
+        );
+    }
 }
 
 fn format_human_readable(size: u64, humread: bool) -> String {
@@ -239,9 +559,28 @@ type SrcStatsSet = BTreeMap<OsString, SrcStats>;
 
 fn process_files(root: &Path, settings: &Settings) -> Result<(Vec<FileEntry>, SrcStatsSet)> {
     let mut walked = 0;
+    let mut ignore_stack = IgnoreStack::new();
     let files = WalkDir::new(&settings.root)
         .into_iter()
-        .filter_entry(|e| !e.file_type().is_dir() || !settings.ignore_dirs.contains(e.file_name()))
+        .filter_entry(move |e| {
+            let is_dir = e.file_type().is_dir();
+            if e.depth() == 0 {
+                // The root directory's own ignore file governs its children,
+                // not itself.
+                ignore_stack.enter_dir(e.path());
+                return true;
+            }
+            if is_dir && settings.ignore_dirs.contains(e.file_name()) {
+                return false;
+            }
+            if ignore_stack.is_ignored(e.path(), is_dir) {
+                return false;
+            }
+            if is_dir {
+                ignore_stack.enter_dir(e.path());
+            }
+            true
+        })
         .filter_map(|entry| {
             walked += 1;
             let entry = entry.ok()?;
@@ -249,9 +588,16 @@ fn process_files(root: &Path, settings: &Settings) -> Result<(Vec<FileEntry>, Sr
                 return None;
             }
             let path = entry.path().to_owned();
-            let ext = path.extension().or_else(|| path.file_name())?;
-            if !settings.extensions.contains(&ext.to_ascii_lowercase()) {
-                return None;
+            let ext_or_name = path.extension().or_else(|| path.file_name())?;
+            let recognized = settings.extensions.contains(&ext_or_name.to_ascii_lowercase());
+            if !recognized {
+                // Without --by-language this is exactly the old check: a
+                // file with no recognized extension is out of scope. With
+                // it, a genuinely extensionless file still gets a chance at
+                // shebang detection once its contents are read.
+                if !settings.by_language || path.extension().is_some() {
+                    return None;
+                }
             }
             Some(Ok(path))
         })
@@ -313,13 +659,12 @@ fn process_file_list(
             }
         };
 
-        process_file(settings, fp, filepath, i, filesize)
+        let ext = filepath.extension().map(|ext| ext.to_owned());
+        process_file(settings, fp, filepath, i, filesize, ext)
     }));
 
     for fe in &filelist {
-        let ext = fe.name.extension().unwrap().to_ascii_lowercase();
-
-        let entry = extstats.entry(ext).or_default();
+        let entry = extstats.entry(fe.group.clone()).or_default();
         entry.lines += fe.lines;
         entry.files += 1;
         entry.size += fe.size;
@@ -338,9 +683,17 @@ fn process_file(
     filepath: PathBuf,
     i: usize,
     filesize: u64,
+    ext: Option<OsString>,
 ) -> Option<FileEntry> {
-    let reader = BufReader::new(fp).lines();
-    let linecount = reader.count();
+    let mut lines = BufReader::new(fp).lines();
+    // Peel off the first line to sniff a shebang without reading the file
+    // twice; `.count()` below still tallies every remaining line the same
+    // way `reader.count()` used to tally all of them.
+    let first = lines.next();
+    let linecount = first.is_some() as usize + lines.count();
+    let first_line = first.and_then(|line| line.ok());
+
+    let group = resolve_group(settings, ext, first_line.as_deref())?;
 
     if settings.listing {
         if settings.enable_html {
@@ -365,72 +718,730 @@ fn process_file(
         name: filepath,
         lines: linecount,
         size: filesize,
+        group,
     })
 }
 
+/// Callback invoked per blob by `RepoSource::visit_blobs` with
+/// `(path, extension, content, size, is_binary)`.
+#[cfg(any(feature = "git2", feature = "gix"))]
+type BlobVisitor<'a> = dyn FnMut(PathBuf, Option<OsString>, &[u8], u64, bool) + 'a;
+
+/// Source of blobs for `process_files_git`, abstracting over which git
+/// implementation is actually used to read them. `process_files_git` itself,
+/// and everything downstream of it (summary/ranking/distribution), stays the
+/// same no matter which `RepoSource` it's handed.
+#[cfg(any(feature = "git2", feature = "gix"))]
+trait RepoSource {
+    /// Visits every blob reachable from `settings.branch` (HEAD if `None`),
+    /// skipping any path component named in `settings.ignore_dirs`, calling
+    /// `visit` with `(path, extension, content, size, is_binary)` for each.
+    /// `extension` is `None` for a path with no extension at all, so the
+    /// caller can still give it a chance via shebang detection. Extension
+    /// allow-listing and the binary skip are also left to the caller so both
+    /// backends can share one filtering code path.
+    fn visit_blobs(&self, settings: &Settings, visit: &mut BlobVisitor) -> Result<()>;
+}
+
 #[cfg(feature = "git2")]
-fn process_files_git(_root: &Path, settings: &Settings) -> Result<(Vec<FileEntry>, SrcStatsSet)> {
+struct Git2Source;
+
+#[cfg(feature = "git2")]
+impl RepoSource for Git2Source {
+    fn visit_blobs(&self, settings: &Settings, visit: &mut BlobVisitor) -> Result<()> {
+        let repo = Repository::open(&settings.root)?;
+        let reference = if let Some(ref branch) = settings.branch {
+            repo.resolve_reference_from_short_name(branch)?
+        } else {
+            repo.head()?
+        };
+        reference
+            .peel_to_tree()?
+            .walk(git2::TreeWalkMode::PostOrder, |root, entry| {
+                (|| -> Option<()> {
+                    let name = entry.name()?;
+                    if entry.kind() != Some(git2::ObjectType::Blob)
+                        || settings.ignore_dirs.contains(&OsString::from(name))
+                    {
+                        return None;
+                    }
+                    if Path::new(root)
+                        .components()
+                        .any(|c| settings.ignore_dirs.contains(c.as_os_str()))
+                    {
+                        return None;
+                    }
+                    let obj = match entry.to_object(&repo) {
+                        Ok(obj) => obj,
+                        Err(e) => {
+                            eprintln!("couldn't get_object: {:?}", e);
+                            return None;
+                        }
+                    };
+                    let blob = obj.peel_to_blob().ok()?;
+                    let path = PathBuf::from(root).join(name);
+                    let ext = path.extension().map(|ext| ext.to_owned());
+
+                    visit(path, ext, blob.content(), blob.size() as u64, blob.is_binary());
+                    Some(())
+                })();
+                TreeWalkResult::Ok
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gix")]
+struct GixSource;
+
+#[cfg(feature = "gix")]
+impl RepoSource for GixSource {
+    fn visit_blobs(&self, settings: &Settings, visit: &mut BlobVisitor) -> Result<()> {
+        let repo = gix::open(&settings.root)?;
+        let commit = if let Some(ref branch) = settings.branch {
+            repo.find_reference(branch)?
+                .peel_to_id_in_place()?
+                .object()?
+                .into_commit()
+        } else {
+            repo.head_commit()?
+        };
+
+        let tree = commit.tree()?;
+        visit_gix_tree(&tree, Path::new(""), settings, visit)
+    }
+}
+
+#[cfg(feature = "gix")]
+fn visit_gix_tree(
+    tree: &gix::Tree,
+    prefix: &Path,
+    settings: &Settings,
+    visit: &mut BlobVisitor,
+) -> Result<()> {
+    for entry in tree.iter() {
+        let entry = entry?;
+        let name = entry.filename().to_string();
+        let path = prefix.join(&name);
+
+        match entry.mode().kind() {
+            gix::object::tree::EntryKind::Tree => {
+                if settings.ignore_dirs.contains(&OsString::from(name)) {
+                    continue;
+                }
+                let subtree = entry.object()?.into_tree();
+                visit_gix_tree(&subtree, &path, settings, visit)?;
+            }
+            gix::object::tree::EntryKind::Blob | gix::object::tree::EntryKind::BlobExecutable => {
+                let ext = path.extension().map(|ext| ext.to_owned());
+                let blob = entry.object()?.into_blob();
+                let is_binary = blob.data.contains(&0);
+                visit(path, ext, &blob.data, blob.data.len() as u64, is_binary);
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "git2", feature = "gix"))]
+fn process_files_git_with(
+    source: &impl RepoSource,
+    settings: &Settings,
+) -> Result<(Vec<FileEntry>, SrcStatsSet)> {
     let mut extstats = SrcStatsSet::new();
     let mut walked = 0;
-    let repo = Repository::open(&settings.root)?;
     let mut i = 0;
     let mut files = vec![];
-    let reference = if let Some(ref branch) = settings.branch {
-        repo.resolve_reference_from_short_name(&branch)?
+
+    source.visit_blobs(settings, &mut |path, ext, content, size, is_binary| {
+        walked += 1;
+        if is_binary {
+            return;
+        }
+        let recognized = ext
+            .as_ref()
+            .map(|ext| settings.extensions.contains(&ext.to_ascii_lowercase()))
+            .unwrap_or(false);
+        if !recognized && (!settings.by_language || ext.is_some()) {
+            return;
+        }
+        if let Some(file_entry) = process_file(settings, content, path, i, size, ext) {
+            let entry = extstats.entry(file_entry.group.clone()).or_default();
+            entry.lines += file_entry.lines;
+            entry.files += 1;
+            entry.size += file_entry.size;
+
+            files.push(file_entry);
+            i += 1;
+        }
+    })?;
+
+    eprintln!("Listing {}/{} files...", files.len(), walked);
+    Ok((files, extstats))
+}
+
+#[cfg(all(test, any(feature = "git2", feature = "gix")))]
+mod repo_source_tests {
+    use super::{process_files_git_with, BlobVisitor, RepoSource, Settings};
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    /// An in-memory `RepoSource` standing in for a real git backend, so
+    /// `process_files_git_with` can be exercised without a repository on
+    /// disk: this is what `Git2Source`/`GixSource` make it generic over.
+    struct FakeSource(Vec<(&'static str, Option<&'static str>, &'static [u8], bool)>);
+
+    impl RepoSource for FakeSource {
+        fn visit_blobs(&self, _settings: &Settings, visit: &mut BlobVisitor) -> super::Result<()> {
+            for (path, ext, content, is_binary) in &self.0 {
+                visit(
+                    PathBuf::from(path),
+                    ext.map(OsString::from),
+                    content,
+                    content.len() as u64,
+                    *is_binary,
+                );
+            }
+            Ok(())
+        }
+    }
+
+    fn test_settings(extensions: &[&str]) -> Settings {
+        Settings {
+            root: PathBuf::new(),
+            listing: false,
+            enable_html: false,
+            ranking: 10,
+            summary: true,
+            enable_distrib: true,
+            use_git: true,
+            branch: None,
+            #[cfg(all(feature = "git2", feature = "gix"))]
+            gix: false,
+            #[cfg(feature = "git2")]
+            churn: false,
+            #[cfg(feature = "git2")]
+            churn_by: None,
+            extensions: extensions.iter().map(OsString::from).collect(),
+            ignore_dirs: HashSet::new(),
+            human_readable: false,
+            utf8: false,
+            tree: false,
+            tree_depth: None,
+            tree_min_percent: 0.,
+            by_language: false,
+            language_table: super::default_language_table(),
+        }
+    }
+
+    #[test]
+    fn check_process_files_git_with_filters_by_extension_and_binary() {
+        let source = FakeSource(vec![
+            ("main.rs", Some("rs"), b"fn main() {}\n" as &[u8], false),
+            ("README.md", Some("md"), b"hello\n", false),
+            ("logo.png", Some("png"), b"\0not text", true),
+        ]);
+        let settings = test_settings(&["rs"]);
+
+        let (files, extstats) = process_files_git_with(&source, &settings).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, PathBuf::from("main.rs"));
+        assert_eq!(extstats[&OsString::from("rs")].files, 1);
+        assert!(!extstats.contains_key(&OsString::from("md")));
+        assert!(!extstats.contains_key(&OsString::from("png")));
+    }
+}
+
+#[cfg(any(feature = "git2", feature = "gix"))]
+fn process_files_git(_root: &Path, settings: &Settings) -> Result<(Vec<FileEntry>, SrcStatsSet)> {
+    #[cfg(all(feature = "gix", feature = "git2"))]
+    {
+        if settings.gix {
+            return process_files_git_with(&GixSource, settings);
+        }
+        process_files_git_with(&Git2Source, settings)
+    }
+    #[cfg(all(feature = "gix", not(feature = "git2")))]
+    {
+        process_files_git_with(&GixSource, settings)
+    }
+    #[cfg(all(feature = "git2", not(feature = "gix")))]
+    {
+        process_files_git_with(&Git2Source, settings)
+    }
+}
+
+#[cfg(feature = "git2")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChurnGranularity {
+    Day,
+    Commit,
+}
+
+#[cfg(feature = "git2")]
+impl std::str::FromStr for ChurnGranularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "commit" => Ok(Self::Commit),
+            _ => Err(format!(r#"expected "day" or "commit", got "{s}""#)),
+        }
+    }
+}
+
+#[cfg(feature = "git2")]
+#[derive(Debug, Default, Clone, Copy)]
+struct ChurnStats {
+    insertions: usize,
+    deletions: usize,
+}
+
+#[cfg(feature = "git2")]
+impl ChurnStats {
+    fn add(&mut self, other: ChurnStats) {
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+
+    fn net(&self) -> i64 {
+        self.insertions as i64 - self.deletions as i64
+    }
+
+    fn tostring(&self) -> String {
+        format!(
+            "+{} -{} (net {:+})",
+            self.insertions,
+            self.deletions,
+            self.net()
+        )
+    }
+
+    fn tohtml(&self) -> String {
+        format!(
+            "<td>+{}</td><td>-{}</td><td>{:+}</td>",
+            self.insertions,
+            self.deletions,
+            self.net()
+        )
+    }
+
+    fn htmlheader() -> &'static str {
+        "<th>insertions</th><th>deletions</th><th>net</th>"
+    }
+}
+
+#[cfg(feature = "git2")]
+type ChurnStatsSet = BTreeMap<OsString, ChurnStats>;
+
+#[cfg(feature = "git2")]
+struct ChurnReport {
+    commits_walked: usize,
+    totals: ChurnStatsSet,
+    by_bucket: BTreeMap<String, ChurnStatsSet>,
+    by_file: BTreeMap<PathBuf, ChurnStats>,
+}
+
+/// Converts a day count since the Unix epoch to a (year, month, day) civil
+/// date, using the algorithm from Howard Hinnant's `chrono`-predecessor
+/// `date` library. Kept local rather than pulling in a date/time crate just
+/// to label churn buckets.
+#[cfg(feature = "git2")]
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { (y + 1) as i32 } else { y as i32 }, m, d)
+}
+
+#[cfg(all(test, feature = "git2"))]
+mod civil_from_days_tests {
+    use super::civil_from_days;
+
+    #[test]
+    fn check_civil_from_days_epoch() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+    }
+
+    #[test]
+    fn check_civil_from_days_known_date() {
+        // 2024-01-01 is 19723 days after the 1970-01-01 epoch.
+        assert_eq!(civil_from_days(19723), (2024, 1, 1));
+    }
+
+    #[test]
+    fn check_civil_from_days_leap_day() {
+        // 2024-02-29 is 19782 days after the epoch.
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+}
+
+#[cfg(feature = "git2")]
+fn churn_bucket_label(granularity: ChurnGranularity, commit: &git2::Commit) -> String {
+    match granularity {
+        ChurnGranularity::Day => {
+            let time = commit.time();
+            let local_seconds = time.seconds() + time.offset_minutes() as i64 * 60;
+            let (y, m, d) = civil_from_days(local_seconds.div_euclid(86400));
+            format!("{y:04}-{m:02}-{d:02}")
+        }
+        ChurnGranularity::Commit => commit.id().to_string()[..7].to_owned(),
+    }
+}
+
+/// Diffs a single commit against its first parent (or against an empty tree
+/// for a root commit) and returns the per-file insertion/deletion counts for
+/// files matching `settings.extensions`, skipping binary deltas. Renames are
+/// resolved via `find_similar` so a moved file shows as touched, not as a
+/// delete plus an add.
+#[cfg(feature = "git2")]
+fn diff_commit(
+    repo: &Repository,
+    settings: &Settings,
+    commit: &git2::Commit,
+) -> Result<BTreeMap<PathBuf, ChurnStats>> {
+    let new_tree = commit.tree()?;
+    let old_tree = if commit.parent_count() > 0 {
+        Some(commit.parent(0)?.tree()?)
     } else {
-        repo.head()?
+        None
     };
-    reference
-        .peel_to_tree()?
-        .walk(git2::TreeWalkMode::PostOrder, |_, entry| {
-            match (|| {
-                let name = entry.name()?;
-                if entry.kind() != Some(git2::ObjectType::Blob)
-                    || settings.ignore_dirs.contains(&OsString::from(name))
-                {
-                    return None;
-                }
-                let obj = match entry.to_object(&repo) {
-                    Ok(obj) => obj,
-                    Err(e) => {
-                        eprintln!("couldn't get_object: {:?}", e);
-                        return None;
-                    }
-                };
-                let blob = obj.peel_to_blob().ok()?;
-                walked += 1;
-                if blob.is_binary() {
-                    return None;
-                }
-                let path: PathBuf = entry.name()?.into();
-                let ext = path.extension()?.to_owned();
-                if !settings.extensions.contains(&ext.to_ascii_lowercase()) {
-                    return None;
-                }
 
-                let filesize = blob.size() as u64;
+    let mut diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)?;
+    diff.find_similar(None)?;
 
-                Some((
-                    ext,
-                    process_file(settings, blob.content(), path, i, filesize)?,
-                ))
-            })() {
-                Some((ext, file_entry)) => {
-                    let entry = extstats.entry(ext).or_default();
-                    entry.lines += file_entry.lines;
-                    entry.files += 1;
-                    entry.size += file_entry.size;
+    let mut file_churn: BTreeMap<PathBuf, ChurnStats> = BTreeMap::new();
 
-                    files.push(file_entry);
-
-                    i += 1;
-                }
+    // `file_cb` returning anything other than `true` aborts the whole diff
+    // walk (libgit2 surfaces it as GIT_EUSER), so extension/binary filtering
+    // has to happen inside the line callback instead of by skipping files
+    // here.
+    diff.foreach(
+        &mut |_delta, _progress| true,
+        None,
+        None,
+        Some(&mut |delta, _hunk, line| {
+            if delta.flags().contains(git2::DiffFlags::BINARY) {
+                return true;
+            }
+            let Some(path) = delta.new_file().path().or_else(|| delta.old_file().path()) else {
+                return true;
+            };
+            let Some(ext) = path.extension() else {
+                return true;
+            };
+            if !settings.extensions.contains(&ext.to_ascii_lowercase()) {
+                return true;
+            }
+            let entry = file_churn.entry(path.to_owned()).or_default();
+            match line.origin() {
+                '+' => entry.insertions += 1,
+                '-' => entry.deletions += 1,
                 _ => (),
             }
-            TreeWalkResult::Ok
-        })?;
-    eprintln!("Listing {}/{} files...", files.len(), walked);
-    Ok((files, extstats))
+            true
+        }),
+    )?;
+
+    Ok(file_churn)
+}
+
+#[cfg(all(test, feature = "git2"))]
+mod diff_commit_tests {
+    use super::{diff_commit, process_churn_git, ChurnGranularity, Settings};
+    use git2::{Repository, Signature};
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    /// Commits `(path, content)` as a single commit on top of the repo's
+    /// current HEAD (or as a root commit if there is none yet), writing each
+    /// path and staging every path in `removed` as deleted.
+    fn commit_files(
+        repo: &Repository,
+        files: &[(&str, &str)],
+        removed: &[&str],
+        message: &str,
+    ) -> git2::Oid {
+        for (path, content) in files {
+            std::fs::write(repo.path().parent().unwrap().join(path), content).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        for path in removed {
+            index.remove_path(Path::new(path)).unwrap();
+        }
+        for (path, _) in files {
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree_oid = index.write_tree().unwrap();
+        let tree = repo.find_tree(tree_oid).unwrap();
+
+        let sig = Signature::now("Test", "test@example.com").unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parent_refs: Vec<&git2::Commit> = parents.iter().collect();
+
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parent_refs)
+            .unwrap()
+    }
+
+    fn test_settings(root: PathBuf) -> Settings {
+        Settings {
+            root,
+            listing: false,
+            enable_html: false,
+            ranking: 10,
+            summary: true,
+            enable_distrib: true,
+            use_git: true,
+            branch: None,
+            #[cfg(all(feature = "git2", feature = "gix"))]
+            gix: false,
+            churn: true,
+            churn_by: Some(ChurnGranularity::Commit),
+            extensions: [OsString::from("rs")].into_iter().collect(),
+            ignore_dirs: HashSet::new(),
+            human_readable: false,
+            utf8: false,
+            tree: false,
+            tree_depth: None,
+            tree_min_percent: 0.,
+            by_language: false,
+            language_table: super::default_language_table(),
+        }
+    }
+
+    #[test]
+    fn check_diff_commit_resolves_rename_instead_of_delete_and_add() {
+        let dir = std::env::temp_dir().join(format!(
+            "srcprof-diff-commit-rename-{:?}",
+            std::thread::current().id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+
+        // libgit2 computes no similarity signature for very small files, so
+        // the fixture is long enough for rename detection to apply.
+        commit_files(
+            &repo,
+            &[("a.rs", "line1\nline2\nline3\nline4\nline5\nline6\nline7\nline8\n")],
+            &[],
+            "add a.rs",
+        );
+        std::fs::remove_file(dir.join("a.rs")).unwrap();
+        commit_files(
+            &repo,
+            &[("b.rs", "line1\nline2\nline3\nline4\nline5\nline6\nline7\nline8\nline9\n")],
+            &["a.rs"],
+            "rename a.rs to b.rs and add a line",
+        );
+
+        let settings = test_settings(dir.clone());
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+
+        let churn = diff_commit(&repo, &settings, &head).unwrap();
+
+        // The rename should be tracked under the new name with only the
+        // appended line counted, not as a 9-line add plus an 8-line delete.
+        assert_eq!(churn.len(), 1);
+        let stats = churn.get(&PathBuf::from("b.rs")).expect("b.rs tracked");
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.deletions, 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_process_churn_git_accumulates_across_commits() {
+        let dir = std::env::temp_dir().join(format!(
+            "srcprof-process-churn-{:?}",
+            std::thread::current().id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+
+        commit_files(&repo, &[("a.rs", "line1\n")], &[], "first commit");
+        commit_files(&repo, &[("a.rs", "line1\nline2\n")], &[], "second commit");
+
+        let settings = test_settings(dir.clone());
+        let report = process_churn_git(&settings).unwrap();
+
+        assert_eq!(report.commits_walked, 2);
+        let stats = report.totals.get(&OsString::from("rs")).expect("rs tracked");
+        assert_eq!(stats.insertions, 2);
+        assert_eq!(stats.deletions, 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(feature = "git2")]
+fn process_churn_git(settings: &Settings) -> Result<ChurnReport> {
+    let repo = Repository::open(&settings.root)?;
+
+    let mut revwalk = repo.revwalk()?;
+    if let Some(ref branch) = settings.branch {
+        let reference = repo.resolve_reference_from_short_name(branch)?;
+        revwalk.push(reference.peel_to_commit()?.id())?;
+    } else {
+        revwalk.push_head()?;
+    }
+    revwalk.set_sorting(git2::Sort::TIME)?;
+
+    let mut totals = ChurnStatsSet::new();
+    let mut by_bucket: BTreeMap<String, ChurnStatsSet> = BTreeMap::new();
+    let mut by_file: BTreeMap<PathBuf, ChurnStats> = BTreeMap::new();
+    let mut commits_walked = 0;
+
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        // A merge just brings in work already counted on its branches, so
+        // merge commits are skipped entirely to avoid double-counting.
+        if commit.parent_count() > 1 {
+            continue;
+        }
+        commits_walked += 1;
+
+        let commit_churn = diff_commit(&repo, settings, &commit)?;
+        let bucket = settings
+            .churn_by
+            .map(|granularity| churn_bucket_label(granularity, &commit));
+
+        for (path, churn) in commit_churn {
+            let Some(ext) = path.extension() else {
+                continue;
+            };
+            let ext = ext.to_ascii_lowercase();
+
+            totals.entry(ext.clone()).or_default().add(churn);
+            by_file.entry(path).or_default().add(churn);
+            if let Some(ref bucket) = bucket {
+                by_bucket
+                    .entry(bucket.clone())
+                    .or_default()
+                    .entry(ext)
+                    .or_default()
+                    .add(churn);
+            }
+        }
+    }
+
+    eprintln!("Walked {commits_walked} commits...");
+    Ok(ChurnReport {
+        commits_walked,
+        totals,
+        by_bucket,
+        by_file,
+    })
+}
+
+#[cfg(feature = "git2")]
+fn show_churn_stats_set(settings: &Settings, stats: &ChurnStatsSet) {
+    let mut total = ChurnStats::default();
+    for (ext, churn) in stats {
+        if settings.enable_html {
+            println!("<tr><td>{:?}</td>{}</tr>", ext, churn.tohtml());
+        } else {
+            println!("{:?}: {}", ext, churn.tostring());
+        }
+        total.add(*churn);
+    }
+    if settings.enable_html {
+        println!("<tr><td>total</td>{}</tr>", total.tohtml());
+    } else {
+        println!("total: {}", total.tostring());
+    }
+}
+
+#[cfg(feature = "git2")]
+fn show_churn(settings: &Settings, report: &ChurnReport) {
+    if settings.enable_html {
+        println!("<h1>Churn over {} commits</h1>", report.commits_walked);
+        println!(r#"<table border="1" cellspacing="0" cellpadding="1">"#);
+        println!("<tr><th>extension</th>{}</tr>", ChurnStats::htmlheader());
+    } else {
+        println!(
+            r#"
+--------------------------
+      Churn over {} commits
+--------------------------
+"#,
+            report.commits_walked
+        );
+    }
+
+    show_churn_stats_set(settings, &report.totals);
+
+    if settings.enable_html {
+        println!("</table><hr>");
+    }
+
+    for (bucket, stats) in &report.by_bucket {
+        if settings.enable_html {
+            println!("<h2>{bucket}</h2>");
+            println!(r#"<table border="1" cellspacing="0" cellpadding="1">"#);
+            println!("<tr><th>extension</th>{}</tr>", ChurnStats::htmlheader());
+        } else {
+            println!("-- {bucket} --");
+        }
+
+        show_churn_stats_set(settings, stats);
+
+        if settings.enable_html {
+            println!("</table><hr>");
+        }
+    }
+
+    if 0 < settings.ranking {
+        let mut by_file: Vec<(&PathBuf, &ChurnStats)> = report.by_file.iter().collect();
+        by_file.sort_by_key(|(_, churn)| Reverse(churn.insertions + churn.deletions));
+
+        if settings.enable_html {
+            println!("<h1>Top {} files by churn</h1>", settings.ranking);
+            println!(r#"<table border="1" cellspacing="0" cellpadding="1">"#);
+            println!("<tr><th>file</th>{}</tr>", ChurnStats::htmlheader());
+        } else {
+            println!(
+                r#"
+--------------------------
+      Top {} files by churn
+--------------------------
+"#,
+                settings.ranking
+            );
+        }
+
+        for (path, churn) in by_file.into_iter().take(settings.ranking as usize) {
+            if settings.enable_html {
+                println!(
+                    "<tr><td>{}</td>{}</tr>",
+                    path.to_string_lossy(),
+                    churn.tohtml()
+                );
+            } else {
+                println!("{}: {}", path.to_string_lossy(), churn.tostring());
+            }
+        }
+
+        if settings.enable_html {
+            println!("</table><hr>");
+        }
+    }
 }
 
 fn show_summary(settings: &Settings, extstats: &SrcStatsSet) {
@@ -438,10 +1449,16 @@ fn show_summary(settings: &Settings, extstats: &SrcStatsSet) {
         return;
     }
 
+    let key_label = if settings.by_language {
+        "language"
+    } else {
+        "extension"
+    };
+
     if settings.enable_html {
         println!("<h1>Summary</h1>");
         println!(r#"<table border="1" cellspacing="0" cellpadding="1">"#);
-        println!("<tr><th>extension</th>{}</tr>", SrcStats::htmlheader());
+        println!("<tr><th>{key_label}</th>{}</tr>", SrcStats::htmlheader());
     } else {
         println!(
             r#"
@@ -600,3 +1617,228 @@ fn show_distribution(settings: &Settings, file_list: &[FileEntry], hconv: impl F
         println!("</table>");
     }
 }
+
+/// A node in the directory hierarchy built from the flat `Vec<FileEntry>`,
+/// rolling line counts and sizes up from files to their containing
+/// directories, like a disk-usage tree measured in source lines.
+#[derive(Default)]
+struct TreeNode {
+    lines: usize,
+    size: u64,
+    children: BTreeMap<OsString, TreeNode>,
+}
+
+fn build_tree(root: &Path, file_list: &[FileEntry]) -> TreeNode {
+    let mut root_node = TreeNode::default();
+
+    for fe in file_list {
+        let rel = fe.name.strip_prefix(root).unwrap_or(&fe.name);
+        let mut node = &mut root_node;
+        for component in rel.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_owned())
+                .or_default();
+        }
+        node.lines += fe.lines;
+        node.size += fe.size;
+    }
+
+    fn rollup(node: &mut TreeNode) -> (usize, u64) {
+        let mut totals = (node.lines, node.size);
+        for child in node.children.values_mut() {
+            let (lines, size) = rollup(child);
+            totals.0 += lines;
+            totals.1 += size;
+        }
+        node.lines = totals.0;
+        node.size = totals.1;
+        totals
+    }
+    rollup(&mut root_node);
+
+    root_node
+}
+
+fn show_tree(settings: &Settings, file_list: &[FileEntry]) {
+    if !settings.tree {
+        return;
+    }
+
+    let root_node = build_tree(&settings.root, file_list);
+
+    if settings.enable_html {
+        println!("<h1>Directory tree</h1>");
+        println!("<pre>");
+    } else {
+        println!(
+            r#"
+--------------------------
+      Tree
+--------------------------
+"#
+        );
+    }
+
+    print_tree_children(settings, &root_node, 0);
+
+    if settings.enable_html {
+        println!("</pre><hr>");
+    }
+}
+
+fn print_tree_entry(settings: &Settings, indent: usize, name: &str, entry: &TreeNode, percent: f64) {
+    let bar_width = if settings.enable_html { 300 } else { 40 };
+    let filled = (percent / 100. * bar_width as f64) as usize;
+
+    if settings.enable_html {
+        println!(
+            r#"{0:indent$}{1} {2} ({3:.1}%) <div style="display:inline-block;background-color:#3070c0;width:{4}px;">&nbsp;</div><br>"#,
+            "",
+            name,
+            format_human_readable(entry.lines as u64, settings.human_readable),
+            percent,
+            filled,
+            indent = indent * 2
+        );
+    } else {
+        let bar: String = "*".repeat(filled);
+        println!(
+            "{0:indent$}{1:<40} {2:>8} ({3:>5.1}%) {4}",
+            "",
+            name,
+            format_human_readable(entry.lines as u64, settings.human_readable),
+            percent,
+            bar,
+            indent = indent * 2
+        );
+    }
+}
+
+/// Splits `node`'s children, sorted descending by line count, into those
+/// whose share of `node`'s total lines is at least `min_percent` (kept,
+/// paired with their percentage) and the rolled-up remainder (collapsed
+/// total and how many children went into it). Pulled out of
+/// `print_tree_children` so the collapse math can be tested without
+/// capturing stdout.
+fn partition_tree_children(
+    node: &TreeNode,
+    min_percent: f64,
+) -> (Vec<(&OsString, &TreeNode, f64)>, TreeNode, usize) {
+    let mut children: Vec<(&OsString, &TreeNode)> = node.children.iter().collect();
+    children.sort_by_key(|(_, child)| Reverse(child.lines));
+
+    let percent_of_parent = |lines: usize| {
+        if node.lines == 0 {
+            0.
+        } else {
+            lines as f64 * 100. / node.lines as f64
+        }
+    };
+
+    let mut kept = vec![];
+    let mut collapsed = TreeNode::default();
+    let mut collapsed_count = 0usize;
+
+    for (name, child) in children {
+        let percent = percent_of_parent(child.lines);
+        if percent < min_percent {
+            collapsed.lines += child.lines;
+            collapsed.size += child.size;
+            collapsed_count += 1;
+        } else {
+            kept.push((name, child, percent));
+        }
+    }
+
+    (kept, collapsed, collapsed_count)
+}
+
+fn print_tree_children(settings: &Settings, node: &TreeNode, depth: usize) {
+    if let Some(max_depth) = settings.tree_depth {
+        if depth >= max_depth {
+            return;
+        }
+    }
+
+    let (kept, collapsed, collapsed_count) =
+        partition_tree_children(node, settings.tree_min_percent);
+
+    for (name, child, percent) in kept {
+        print_tree_entry(settings, depth, &name.to_string_lossy(), child, percent);
+        print_tree_children(settings, child, depth + 1);
+    }
+
+    if collapsed_count > 0 {
+        let percent = if node.lines == 0 {
+            0.
+        } else {
+            collapsed.lines as f64 * 100. / node.lines as f64
+        };
+        print_tree_entry(
+            settings,
+            depth,
+            &format!("... ({} more)", collapsed_count),
+            &collapsed,
+            percent,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tree_tests {
+    use super::{build_tree, partition_tree_children, FileEntry};
+    use std::path::{Path, PathBuf};
+
+    fn entry(name: &str, lines: usize) -> FileEntry {
+        FileEntry {
+            name: PathBuf::from(name),
+            lines,
+            size: 0,
+            group: "rs".into(),
+        }
+    }
+
+    #[test]
+    fn check_build_tree_rolls_up_nested_dirs() {
+        let files = vec![
+            entry("/repo/src/main.rs", 100),
+            entry("/repo/src/sub/a.rs", 10),
+            entry("/repo/README.md", 5),
+        ];
+        let root = build_tree(Path::new("/repo"), &files);
+
+        assert_eq!(root.lines, 115);
+        let src = &root.children[std::ffi::OsStr::new("src")];
+        assert_eq!(src.lines, 110);
+        let sub = &src.children[std::ffi::OsStr::new("sub")];
+        assert_eq!(sub.lines, 10);
+        let readme = &root.children[std::ffi::OsStr::new("README.md")];
+        assert_eq!(readme.lines, 5);
+    }
+
+    #[test]
+    fn check_partition_tree_children_collapses_below_threshold() {
+        let files = vec![
+            entry("/repo/big.rs", 90),
+            entry("/repo/small.rs", 10),
+        ];
+        let root = build_tree(Path::new("/repo"), &files);
+
+        let (kept, collapsed, collapsed_count) = partition_tree_children(&root, 50.);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].0, std::ffi::OsStr::new("big.rs"));
+        assert_eq!(collapsed_count, 1);
+        assert_eq!(collapsed.lines, 10);
+    }
+
+    #[test]
+    fn check_partition_tree_children_keeps_all_above_threshold() {
+        let files = vec![entry("/repo/a.rs", 60), entry("/repo/b.rs", 40)];
+        let root = build_tree(Path::new("/repo"), &files);
+
+        let (kept, _collapsed, collapsed_count) = partition_tree_children(&root, 10.);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(collapsed_count, 0);
+    }
+}